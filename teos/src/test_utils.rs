@@ -7,14 +7,17 @@
  * at your option.
 */
 
+use std::collections::HashSet;
+
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
 use bitcoin::hash_types::BlockHash;
-use bitcoin::hash_types::Txid;
+use bitcoin::hash_types::{TxMerkleNode, Txid};
 use bitcoin::hashes::hex::FromHex;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::network::constants::Network;
 use bitcoin::util::hash::bitcoin_merkle_root;
 use bitcoin::util::psbt::serialize::Deserialize;
@@ -29,12 +32,29 @@ use teos_common::cryptography::encrypt;
 
 use crate::extended_appointment::ExtendedAppointment;
 
+/// Result of a filtered-block fetch: the full block, just the header (for a block whose
+/// body was never downloaded, mirroring `without_blocks`), or the header alongside the
+/// subset of transactions the caller asked for via a locator set. Used by
+/// `Blockchain::get_filtered_block` to exercise a light backend that never downloads full
+/// blocks, which `BlockSource::get_block` (constrained by the trait's own signature) can't
+/// represent.
+#[derive(Clone, Debug)]
+pub(crate) enum BlockData {
+    FullBlock(Block),
+    HeaderOnly(BlockHeader),
+    FilteredBlock {
+        header: BlockHeader,
+        txdata: Vec<Transaction>,
+    },
+}
+
 #[derive(Clone, Default, Debug)]
 pub(crate) struct Blockchain {
     pub blocks: Vec<Block>,
     without_blocks: Option<std::ops::RangeFrom<usize>>,
     without_headers: bool,
     malformed_headers: bool,
+    filtered_blocks: Option<HashSet<[u8; 16]>>,
 }
 
 impl Blockchain {
@@ -57,16 +77,19 @@ impl Blockchain {
             let prev_block = &self.blocks[i - 1];
             let prev_blockhash = prev_block.block_hash();
             let time = prev_block.header.time + height as u32;
+            let txdata = vec![get_coinbase_tx(i)];
+            let merkle_root =
+                bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid().as_hash())).into();
             self.blocks.push(Block {
                 header: BlockHeader {
                     version: 0,
                     prev_blockhash,
-                    merkle_root: Default::default(),
+                    merkle_root,
                     time,
                     bits,
                     nonce: 0,
                 },
-                txdata: vec![],
+                txdata,
             });
         }
         self
@@ -111,6 +134,13 @@ impl Blockchain {
         }
     }
 
+    pub fn with_filtered_blocks(self, locator_set: HashSet<[u8; 16]>) -> Self {
+        Self {
+            filtered_blocks: Some(locator_set),
+            ..self
+        }
+    }
+
     pub fn fork_at_height(&self, height: usize) -> Self {
         assert!(height + 1 < self.blocks.len());
         let mut blocks = self.blocks.clone();
@@ -185,6 +215,82 @@ impl Blockchain {
     pub async fn get_block_count(&self) -> usize {
         self.blocks.len()
     }
+
+    /// Fetches the block matching `header_hash` the way a light backend would: if the
+    /// height falls in `without_blocks`, only the header is returned; otherwise, if
+    /// `filtered_blocks` is set, only the transactions whose `txid()[..16]` is in the
+    /// configured locator set are returned alongside the full header, instead of the
+    /// whole block. Lives outside of `BlockSource::get_block` because that trait's
+    /// signature is fixed to `Block`.
+    pub fn get_filtered_block(&self, header_hash: &BlockHash) -> Option<BlockData> {
+        let (height, block) = self
+            .blocks
+            .iter()
+            .enumerate()
+            .find(|(_, block)| block.header.block_hash() == *header_hash)?;
+
+        if let Some(without_blocks) = &self.without_blocks {
+            if without_blocks.contains(&height) {
+                return Some(BlockData::HeaderOnly(block.header));
+            }
+        }
+
+        match &self.filtered_blocks {
+            Some(locator_set) => {
+                let txdata = block
+                    .txdata
+                    .iter()
+                    .filter(|tx| {
+                        let mut locator = [0; 16];
+                        locator.copy_from_slice(&tx.txid()[..16]);
+                        locator_set.contains(&locator)
+                    })
+                    .cloned()
+                    .collect();
+
+                Some(BlockData::FilteredBlock {
+                    header: block.header,
+                    txdata,
+                })
+            }
+            None => Some(BlockData::FullBlock(block.clone())),
+        }
+    }
+
+    /// Builds the authentication path from `txid`'s leaf up to the merkle root of the
+    /// block at `height`, so a client can be handed a compact SPV proof that a
+    /// transaction was actually mined rather than trusting the tower's word for it.
+    /// Returns, at each level, the sibling hash and whether that sibling sits on the
+    /// right of our branch.
+    pub fn merkle_proof(&self, height: usize, txid: &Txid) -> Option<Vec<(Sha256dHash, bool)>> {
+        let block = self.blocks.get(height)?;
+        let mut hashes: Vec<Sha256dHash> =
+            block.txdata.iter().map(|tx| tx.txid().as_hash()).collect();
+        let mut index = hashes.iter().position(|hash| *hash == txid.as_hash())?;
+
+        let mut proof = Vec::new();
+        while hashes.len() > 1 {
+            if hashes.len() % 2 == 1 {
+                hashes.push(*hashes.last().unwrap());
+            }
+
+            let sibling_is_right = index % 2 == 0;
+            let sibling_index = if sibling_is_right {
+                index + 1
+            } else {
+                index - 1
+            };
+            proof.push((hashes[sibling_index], sibling_is_right));
+
+            hashes = hashes
+                .chunks(2)
+                .map(|pair| hash_pair(pair[0], pair[1]))
+                .collect();
+            index /= 2;
+        }
+
+        Some(proof)
+    }
 }
 
 impl BlockSource for Blockchain {
@@ -245,6 +351,55 @@ impl BlockSource for Blockchain {
     }
 }
 
+fn hash_pair(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
+    let mut engine = Sha256dHash::engine();
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    Sha256dHash::from_engine(engine)
+}
+
+/// Recomputes the merkle root along `proof` starting from `txid` and checks it matches
+/// `merkle_root`, i.e. verifies the authentication path returned by `Blockchain::merkle_proof`.
+pub(crate) fn verify_merkle_proof(
+    txid: &Txid,
+    proof: &[(Sha256dHash, bool)],
+    merkle_root: TxMerkleNode,
+) -> bool {
+    let mut current = txid.as_hash();
+    for (sibling, sibling_is_right) in proof {
+        current = if *sibling_is_right {
+            hash_pair(current, *sibling)
+        } else {
+            hash_pair(*sibling, current)
+        };
+    }
+
+    current == merkle_root.as_hash()
+}
+
+/// Builds a synthetic coinbase transaction so that blocks generated by `with_height`
+/// carry a real merkle commitment instead of an empty `txdata`. The height is pushed
+/// into `script_sig` (BIP34-style) so that blocks at different heights don't end up with
+/// the same coinbase txid and merkle root.
+pub(crate) fn get_coinbase_tx(height: usize) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_int(height as i64).into_script(),
+            witness: Vec::new(),
+            sequence: 0,
+        }],
+        output: vec![TxOut {
+            script_pubkey: Builder::new()
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+                .into_script(),
+            value: 0,
+        }],
+    }
+}
+
 pub(crate) fn get_random_tx() -> Transaction {
     let mut rng = rand::thread_rng();
     let prev_txid_bytes = rng.gen::<[u8; 32]>();
@@ -287,4 +442,151 @@ pub(crate) fn generate_dummy_appointment(dispute_txid: Option<&Txid>) -> Extende
     let start_block = 42;
 
     ExtendedAppointment::new(appointment, user_id, user_signature, start_block)
-}
\ No newline at end of file
+}
+
+/// Builds a self-consistent funding -> commitment -> penalty scenario and mines the
+/// commitment (the dispute tx) into `chain`, so integration tests can exercise the full
+/// detect-decrypt-rebroadcast flow instead of relying on isolated, unrelated dummies.
+pub(crate) fn generate_breach_scenario(
+    chain: &mut Blockchain,
+) -> (ExtendedAppointment, Transaction) {
+    let funding_tx = get_random_tx();
+
+    let commitment_tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(funding_tx.txid(), 0),
+            script_sig: Script::new(),
+            witness: Vec::new(),
+            sequence: 0,
+        }],
+        output: vec![TxOut {
+            script_pubkey: Builder::new().push_int(1).into_script(),
+            value: funding_tx.output[0].value,
+        }],
+    };
+
+    let penalty_tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(commitment_tx.txid(), 0),
+            script_sig: Script::new(),
+            witness: Vec::new(),
+            sequence: 0,
+        }],
+        output: vec![TxOut {
+            script_pubkey: Builder::new().push_int(1).into_script(),
+            value: commitment_tx.output[0].value,
+        }],
+    };
+
+    let commitment_txid = commitment_tx.txid();
+    let mut locator = [0; 16];
+    locator.copy_from_slice(&commitment_txid[..16]);
+
+    let encrypted_blob = encrypt(&penalty_tx, &commitment_txid).unwrap();
+    let appointment = Appointment::new(locator, encrypted_blob, 21);
+    let user_id = [2; 16];
+    let user_signature = [5, 6, 7, 8].to_vec();
+    let start_block = 42;
+
+    chain.generate_with_txs(vec![commitment_tx]);
+
+    (
+        ExtendedAppointment::new(appointment, user_id, user_signature, start_block),
+        penalty_tx,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merkle_proof_validates_against_the_block_merkle_root() {
+        let chain = Blockchain::default().with_height_and_txs(2, Some(4));
+        let height = chain.blocks.len() - 1;
+        let block = &chain.blocks[height];
+        let txid = block.txdata[1].txid();
+
+        let proof = chain.merkle_proof(height, &txid).unwrap();
+        assert!(verify_merkle_proof(&txid, &proof, block.header.merkle_root));
+    }
+
+    #[test]
+    fn merkle_proof_does_not_validate_against_another_blocks_root() {
+        let chain = Blockchain::default().with_height_and_txs(2, Some(4));
+        let height = chain.blocks.len() - 1;
+        let txid = chain.blocks[height].txdata[1].txid();
+        let other_root = chain.blocks[height - 1].header.merkle_root;
+
+        let proof = chain.merkle_proof(height, &txid).unwrap();
+        assert!(!verify_merkle_proof(&txid, &proof, other_root));
+    }
+
+    #[test]
+    fn breach_scenario_penalty_spends_the_mined_commitment() {
+        let mut chain = Blockchain::default();
+        let (_appointment, penalty_tx) = generate_breach_scenario(&mut chain);
+
+        let commitment_tx = chain.blocks.last().unwrap().txdata.last().unwrap();
+        assert_eq!(
+            penalty_tx.input[0].previous_output.txid,
+            commitment_tx.txid()
+        );
+    }
+
+    fn locator_of(tx: &Transaction) -> [u8; 16] {
+        let mut locator = [0; 16];
+        locator.copy_from_slice(&tx.txid()[..16]);
+        locator
+    }
+
+    #[test]
+    fn get_filtered_block_returns_the_full_block_when_unset() {
+        let chain = Blockchain::default().with_height_and_txs(1, Some(4));
+        let header_hash = chain.tip().header.block_hash();
+
+        match chain.get_filtered_block(&header_hash).unwrap() {
+            BlockData::FullBlock(block) => {
+                assert_eq!(block.txdata, chain.blocks.last().unwrap().txdata)
+            }
+            other => panic!("expected FullBlock, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn get_filtered_block_strips_txs_outside_the_locator_set() {
+        let chain = Blockchain::default().with_height_and_txs(1, Some(4));
+        let header_hash = chain.tip().header.block_hash();
+        let all_txs = chain.blocks.last().unwrap().txdata.clone();
+        let locator_set = HashSet::from([locator_of(&all_txs[0]), locator_of(&all_txs[1])]);
+
+        let chain = chain.with_filtered_blocks(locator_set);
+
+        match chain.get_filtered_block(&header_hash).unwrap() {
+            BlockData::FilteredBlock { header, txdata } => {
+                assert_eq!(header, chain.blocks.last().unwrap().header);
+                assert_eq!(txdata, all_txs[..2]);
+            }
+            other => panic!("expected FilteredBlock, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn get_filtered_block_returns_header_only_for_withheld_blocks() {
+        let chain = Blockchain::default()
+            .with_height_and_txs(1, Some(4))
+            .without_blocks(1..);
+        let header_hash = chain.tip().header.block_hash();
+
+        match chain.get_filtered_block(&header_hash).unwrap() {
+            BlockData::HeaderOnly(header) => {
+                assert_eq!(header, chain.blocks.last().unwrap().header)
+            }
+            other => panic!("expected HeaderOnly, got {:?}", other),
+        }
+    }
+}